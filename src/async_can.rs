@@ -20,6 +20,7 @@ use core::ffi::c_size_t;
 use std::ffi::c_void;
 use std::mem::{MaybeUninit, size_of};
 use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_io::Async;
 use libc::can_frame;
@@ -65,6 +66,54 @@ impl AsyncCanSocket {
         Ok(frame.into())
     }
 
+    /// Read a frame together with the time it was captured.
+    ///
+    /// The kernel's receive timestamp is requested via `SIOCGSTAMP` on the CAN fd; when the driver
+    /// does not provide one we fall back to a software clock read at dequeue time. The timestamp is
+    /// expressed as the elapsed time since the UNIX epoch so the receiving side can compare it
+    /// against its own clock to estimate end-to-end transport latency.
+    pub async fn read_frame_with_timestamp(&self) -> std::io::Result<(CanFrame, Duration)> {
+        let mut frame = MaybeUninit::<can_frame>::uninit();
+        let mut stamp = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        self.watcher
+            .read_with(|fd| {
+                let ret = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        frame.as_mut_ptr() as *mut c_void,
+                        size_of::<can_frame>() as c_size_t,
+                    )
+                };
+                if ret > 0 {
+                    // Best-effort kernel capture timestamp; left zeroed (software fallback) on error.
+                    unsafe {
+                        libc::ioctl(fd.as_raw_fd(), libc::SIOCGSTAMP as _, &mut stamp);
+                    }
+                    Ok(ret)
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            })
+            .await?;
+
+        // Safety: Return value was okay and we trust the c library to have properly
+        // filled the value.
+        let frame = unsafe { frame.assume_init() };
+
+        let timestamp = if stamp.tv_sec != 0 || stamp.tv_usec != 0 {
+            Duration::new(stamp.tv_sec as u64, (stamp.tv_usec as u32) * 1000)
+        } else {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        };
+
+        Ok((frame.into(), timestamp))
+    }
+
     pub async fn write_frame(&self, frame: &CanFrame) -> std::io::Result<()> {
         self.watcher
             .write_with(|fd| {
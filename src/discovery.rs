@@ -0,0 +1,166 @@
+/*
+ *  Copyright (c) 2022 Janosch Reppnow.
+ *
+ *  This program is free software; you can redistribute it and/or
+ *  modify it under the terms of the GNU Lesser General Public
+ *  License as published by the Free Software Foundation; either
+ *  version 3 of the License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *  Lesser General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Lesser General Public License
+ *  along with this program; if not, write to the Free Software Foundation,
+ *  Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ */
+
+//! Peer discovery for deployments behind consumer NATs.
+//!
+//! Two independent pieces, both driven from `main` before [`crate::udp::create_udp_sockets`]:
+//!
+//! * [`map_port`] asks the local Internet Gateway Device (via UPnP/IGD) to forward our bind port,
+//!   returning the public endpoint packets can reach us on.
+//! * [`rendezvous`] periodically publishes our public endpoint as a small encrypted beacon to a
+//!   shared rendezvous address and learns the peer's endpoint from its beacons, so two NATed nodes
+//!   can find each other without statically known public IPs.
+//!
+//! **Experimental, requires an external broker.** Neither piece completes a NAT traversal on its
+//! own. [`rendezvous`] only reflects a beacon back to us if *something* listening at
+//! `rendezvous_address` relays it there; this module implements no such relay or STUN-style
+//! self-reflection, so a working deployment needs one operated out of band. And the UPnP mapping
+//! [`map_port`] requests is not renewed before its one-hour lease expires (see its doc comment), so
+//! a tunnel that outlives the lease goes dark silently. Treat both as building blocks for a future
+//! NAT-traversal story, not a turnkey one yet.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_io::Timer;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures::{select, FutureExt};
+use rand::RngCore;
+use smol::net::UdpSocket;
+
+/// How often a node republishes its beacon while waiting to learn the peer's endpoint.
+const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long [`rendezvous`] waits for the peer's beacon before giving up. Without this, a missing or
+/// misconfigured broker at the rendezvous address leaves `main` blocked forever with no diagnostic.
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Request a UPnP/IGD port mapping for `bind`'s port and return the resulting public endpoint.
+///
+/// The peer's UDP packets can then traverse our NAT to the mapped port. The mapping is requested
+/// with a one-hour lease; IGD leases are *not* refreshed by data traffic, so a tunnel running
+/// longer than the lease must call this again to renew the mapping before it expires.
+pub async fn map_port(bind: &SocketAddr) -> anyhow::Result<SocketAddr> {
+    use igd::aio::search_gateway;
+    use igd::PortMappingProtocol;
+
+    let gateway = search_gateway(Default::default())
+        .await
+        .context("Searching for an IGD gateway..")?;
+
+    let SocketAddr::V4(local) = bind else {
+        anyhow::bail!("UPnP port mapping is only supported for IPv4 bind addresses!");
+    };
+
+    gateway
+        .add_port(
+            PortMappingProtocol::UDP,
+            local.port(),
+            *local,
+            /* lease seconds */ 3600,
+            "cannelloni",
+        )
+        .await
+        .context("Requesting a UPnP port mapping..")?;
+
+    let external_ip = gateway
+        .get_external_ip()
+        .await
+        .context("Querying the external IP from the gateway..")?;
+
+    Ok(SocketAddr::new(external_ip.into(), local.port()))
+}
+
+/// Exchange encrypted beacons via `rendezvous_address` until the peer's public endpoint is learned,
+/// or [`RENDEZVOUS_TIMEOUT`] passes with nothing useful received, whichever comes first.
+///
+/// `public_endpoint` is what we advertise (our UPnP mapping or configured bind address); `key` is
+/// the shared pre-shared key, so only nodes holding the passphrase can read or spoof a beacon.
+///
+/// This only succeeds if something at `rendezvous_address` relays our beacon to the peer and the
+/// peer's beacon back to us (see the module-level doc comment) — there is no such broker or
+/// STUN-style reflection built into this crate.
+pub async fn rendezvous(
+    bind: &SocketAddr,
+    rendezvous_address: &SocketAddr,
+    public_endpoint: SocketAddr,
+    key: &[u8; 32],
+) -> anyhow::Result<SocketAddr> {
+    let socket = UdpSocket::bind(bind)
+        .await
+        .context(format!("Binding rendezvous socket to: {bind}.."))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let beacon = seal(&cipher, public_endpoint.to_string().as_bytes());
+
+    let mut timer = Timer::after(Duration::from_secs(0)).fuse();
+    let mut deadline = Timer::after(RENDEZVOUS_TIMEOUT).fuse();
+    let mut buffer = [0u8; 512];
+    loop {
+        select! {
+            _ = &mut deadline => {
+                anyhow::bail!(
+                    "Timed out after {RENDEZVOUS_TIMEOUT:?} waiting for a peer beacon via {rendezvous_address}; \
+                     is a broker there relaying beacons between peers?"
+                );
+            }
+            _ = &mut timer => {
+                socket
+                    .send_to(&beacon, rendezvous_address)
+                    .await
+                    .context("Publishing rendezvous beacon..")?;
+                timer = Timer::after(BEACON_INTERVAL).fuse();
+            }
+            received = socket.recv_from(&mut buffer).fuse() => {
+                let (n, _) = received.context("Reading from rendezvous socket..")?;
+                if let Some(endpoint) = open(&cipher, &buffer[..n])
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .and_then(|text| text.parse::<SocketAddr>().ok())
+                {
+                    // Ignore the reflection of our own beacon.
+                    if endpoint != public_endpoint {
+                        return Ok(endpoint);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Encrypt a beacon payload as `nonce || ciphertext || tag`.
+fn seal(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .expect("ChaCha20-Poly1305 encryption cannot fail for in-memory input");
+    let mut out = Vec::with_capacity(nonce.len() + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    out
+}
+
+/// Verify and decrypt a beacon produced by [`seal`], returning `None` on authentication failure.
+fn open(cipher: &ChaCha20Poly1305, bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = bytes.split_at(12);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
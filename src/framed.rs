@@ -0,0 +1,229 @@
+/*
+ *  Copyright (c) 2022 Janosch Reppnow.
+ *
+ *  This program is free software; you can redistribute it and/or
+ *  modify it under the terms of the GNU Lesser General Public
+ *  License as published by the Free Software Foundation; either
+ *  version 3 of the License, or (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ *  Lesser General Public License for more details.
+ *
+ *  You should have received a copy of the GNU Lesser General Public License
+ *  along with this program; if not, write to the Free Software Foundation,
+ *  Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ */
+
+//! A framed view of the cannelloni wire protocol on top of a UDP socket.
+//!
+//! [`CannelloniFramed`] wraps a [`UdpSocket`] together with a [`MessageSerializer`] and implements
+//! both [`Sink<CanAnyFrame>`] and [`Stream<Item = anyhow::Result<CanAnyFrame>>`], so the protocol
+//! can be driven with the usual futures combinators instead of the hand-rolled loops in [`crate::udp`].
+//! Frames fed into the sink are buffered into a bundle and flushed either when the next frame would
+//! exceed the no-fragment payload size or when the caller explicitly flushes (e.g. from a
+//! force-after timer); the stream side decodes incoming bundles through [`MessageReader`].
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Context as _;
+use futures::{ready, Sink, Stream};
+use smol::net::UdpSocket;
+use socketcan::CanAnyFrame;
+
+use crate::proto::{self, Crypto, MessageReader, MessageSerializer};
+use crate::udp::{is_known_peer, UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE};
+
+type SendFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+type RecvFuture = Pin<Box<dyn Future<Output = anyhow::Result<Vec<CanAnyFrame>>> + Send>>;
+
+/// A [`Sink`]/[`Stream`] adapter over the cannelloni UDP protocol.
+pub struct CannelloniFramed {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    serializer: MessageSerializer,
+    key: Option<[u8; 32]>,
+    local_address: SocketAddr,
+    sending: Option<SendFuture>,
+    receiving: Option<RecvFuture>,
+    decoded: std::collections::VecDeque<CanAnyFrame>,
+}
+
+impl CannelloniFramed {
+    pub fn new(
+        socket: UdpSocket,
+        targets: Vec<SocketAddr>,
+        local_address: SocketAddr,
+        key: Option<[u8; 32]>,
+        compress: bool,
+    ) -> Self {
+        let mut serializer = match &key {
+            Some(key) => MessageSerializer::with_key(key),
+            None => MessageSerializer::new(),
+        };
+        serializer.set_compress(compress);
+        Self {
+            socket,
+            targets,
+            serializer,
+            key,
+            local_address,
+            sending: None,
+            receiving: None,
+            decoded: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Finalize the buffered bundle and arm the in-flight send future that fans it out to every peer.
+    fn start_flush(&mut self) {
+        let bytes = self.serializer.finalize().data().to_vec();
+        let socket = self.socket.clone();
+        let targets = self.targets.clone();
+        self.sending = Some(Box::pin(async move {
+            for target in &targets {
+                socket.send_to(&bytes, target).await?;
+            }
+            Ok(())
+        }));
+    }
+
+    /// Drive any in-flight send to completion.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        if let Some(sending) = &mut self.sending {
+            ready!(sending.as_mut().poll(cx)).context("Sending frame bundle via UDP..")?;
+            self.sending = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Sink<CanAnyFrame> for CannelloniFramed {
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: CanAnyFrame) -> anyhow::Result<()> {
+        let this = self.get_mut();
+        // Flush the current bundle first if appending this frame would overflow a single datagram.
+        // Sealing appends a nonce and auth tag in `finalize`, so reserve that overhead when a key
+        // is in use to keep the finalized bundle within the no-fragment budget.
+        let max_payload = UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE
+            - if this.key.is_some() {
+                proto::CRYPTO_OVERHEAD
+            } else {
+                0
+            };
+        if !this.serializer.is_empty()
+            && this.serializer.len() + proto::encoded_size(&item) > max_payload
+        {
+            this.start_flush();
+        }
+        this.serializer.push_frame(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if !this.serializer.is_empty() {
+            this.start_flush();
+            ready!(this.poll_send(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl Stream for CannelloniFramed {
+    type Item = anyhow::Result<CanAnyFrame>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(frame) = this.decoded.pop_front() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+
+            if this.receiving.is_none() {
+                let socket = this.socket.clone();
+                let local_address = this.local_address;
+                let remotes = this.targets.clone();
+                let key = this.key;
+                this.receiving = Some(Box::pin(decode_one(socket, local_address, remotes, key)));
+            }
+
+            let receiving = this.receiving.as_mut().expect("just armed above");
+            let frames = ready!(receiving.as_mut().poll(cx));
+            this.receiving = None;
+            match frames {
+                Ok(frames) => this.decoded.extend(frames),
+                Err(error) => return Poll::Ready(Some(Err(error))),
+            }
+        }
+    }
+}
+
+/// Receive datagrams until one decodes into a bundle and return that bundle's frames, skipping our
+/// own echoed packets, datagrams from a peer outside `remotes`, and bundles that fail to parse or
+/// authenticate. Frames inside the bundle that fail to decode are dropped, so the returned vector
+/// can be empty.
+async fn decode_one(
+    socket: UdpSocket,
+    local_address: SocketAddr,
+    remotes: Vec<SocketAddr>,
+    key: Option<[u8; 32]>,
+) -> anyhow::Result<Vec<CanAnyFrame>> {
+    use futures::StreamExt;
+
+    let mut buffer = [0u8; UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE];
+    loop {
+        let (n, peer) = socket
+            .recv_from(&mut buffer)
+            .await
+            .context("Failed to read from UDP socket!")?;
+        if peer == local_address {
+            continue;
+        }
+
+        // The unconnected `send_to`/`recv_from` socket accepts datagrams from anyone who knows the
+        // bind port; only decode bundles from a configured peer. Skipped for the single-multicast
+        // case, where legitimate senders are any current group member, not just `remotes` itself.
+        if !is_known_peer(&peer, &remotes) {
+            continue;
+        }
+
+        let stream = match &key {
+            Some(key) => {
+                let crypto = Crypto::new(key);
+                match MessageReader::try_read_encrypted(&buffer[..n], &crypto).await? {
+                    Some(reader) => reader.into_stream(),
+                    None => continue,
+                }
+            }
+            None => match MessageReader::try_read(&buffer[..n]).await? {
+                Some(reader) => reader.into_stream(),
+                None => continue,
+            },
+        };
+
+        // Drop individual frames that fail to decode instead of failing the whole datagram, so a
+        // single malformed frame cannot terminate the stream.
+        futures::pin_mut!(stream);
+        let mut frames = Vec::new();
+        while let Some(entry) = stream.next().await {
+            if let Ok(frame) = entry {
+                frames.push(frame);
+            }
+        }
+        return Ok(frames);
+    }
+}
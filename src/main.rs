@@ -22,6 +22,7 @@
 use std::time::Duration;
 
 use anyhow::Context;
+use async_io::Timer;
 use clap::Parser;
 use futures::prelude::*;
 use futures::select;
@@ -29,8 +30,11 @@ use smol::net::SocketAddr;
 use socketcan::Socket;
 
 use crate::async_can::AsyncCanSocket;
+use crate::framed::CannelloniFramed;
 
 mod async_can;
+mod discovery;
+mod framed;
 mod proto;
 mod udp;
 
@@ -41,9 +45,12 @@ struct Args {
     #[arg(long, short)]
     bind: SocketAddr,
 
-    /// Remote address to connect to.
-    #[arg(long, short)]
-    remote: SocketAddr,
+    /// Remote address to forward frames to. May be given multiple times to mirror one bus to
+    /// several peers (point-to-multipoint); a single multicast address still uses the multicast path.
+    /// Not required when `--rendezvous` is given, since the peer endpoint is then discovered
+    /// dynamically instead of configured statically.
+    #[arg(long, short, required_unless_present = "rendezvous")]
+    remote: Vec<SocketAddr>,
 
     /// CAN interface name to forward frames to and from.
     #[arg(long, short)]
@@ -52,21 +59,144 @@ struct Args {
     /// After how many milliseconds a packet must be sent, regardless of how many frames it contains.
     #[arg(long, short, default_value_t = 50)]
     force_after_ms: usize,
+
+    /// Pre-shared passphrase enabling authenticated encryption (ChaCha20-Poly1305) of every bundle.
+    ///
+    /// All peers on the tunnel must pass the same passphrase; without it traffic is sent in cleartext.
+    #[arg(long, short)]
+    key: Option<String>,
+
+    /// Enable reliable delivery: acknowledge received bundles and retransmit ones the peer reports missing.
+    #[arg(long)]
+    reliable: bool,
+
+    /// DEFLATE-compress each bundle's frame region when it shrinks the payload (transparent to peers).
+    #[arg(long)]
+    compress: bool,
+
+    /// Prefix each forwarded frame with its capture timestamp so the receiver can log end-to-end
+    /// transport latency. Peers without the feature ignore such bundles (reserved `op_code` bit).
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Request a UPnP/IGD port mapping for the bind port so a peer can reach us through NAT.
+    #[arg(long)]
+    upnp: bool,
+
+    /// Rendezvous address to exchange encrypted endpoint beacons through, learning the peer's
+    /// current public endpoint dynamically instead of a statically configured `--remote`. Requires
+    /// `--key` to authenticate beacons and `--upnp`, since the beacon advertises the mapped public
+    /// endpoint; there is no STUN-style reflection fallback to learn it otherwise.
+    ///
+    /// Experimental: this only finds the peer if something at this address relays beacons between
+    /// the two sides (see `discovery::rendezvous`); gives up after a minute if nothing useful is
+    /// received.
+    #[arg(long, requires = "upnp")]
+    rendezvous: Option<SocketAddr>,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     smol::block_on(async {
-        let (udp_sender, udp_receiver) = udp::create_udp_sockets(&args.bind, &args.remote).await.context("Creating UDP sockets..")?;
+        let key = args.key.as_deref().map(proto::derive_key);
+
+        // Resolve the set of peers to bridge to, optionally opening a NAT pinhole and/or learning
+        // the peer endpoint through the rendezvous beacon before the tunnel's UDP sockets are bound.
+        let mut remotes = args.remote.clone();
+        let public_endpoint = if args.upnp {
+            discovery::map_port(&args.bind)
+                .await
+                .context("Mapping the bind port via UPnP..")?
+        } else {
+            args.bind
+        };
+        if let Some(rendezvous) = args.rendezvous {
+            let key = key
+                .as_ref()
+                .context("--rendezvous requires --key to authenticate beacons..")?;
+            let peer = discovery::rendezvous(&args.bind, &rendezvous, public_endpoint, key)
+                .await
+                .context("Discovering the peer endpoint via rendezvous..")?;
+            // The discovered endpoint replaces any statically configured remotes rather than
+            // supplementing them: rendezvous exists precisely because the peer's address isn't
+            // known up front, so `--remote` (if given at all) is not a real peer to also fan out to.
+            remotes = vec![peer];
+        }
+
+        let (udp_sender, udp_receiver) = udp::create_udp_sockets(&args.bind, &remotes).await.context("Creating UDP sockets..")?;
 
         let can_socket: AsyncCanSocket = socketcan::CanSocket::open(&args.can).context("Trying to open async can socket..").context("Creating CAN socket..")?.into();
 
-        select! {
-            sent = udp::send(&can_socket, &udp_sender, Duration::from_millis(args.force_after_ms as u64), &args.remote).fuse() => sent.context("Trying to send frames to the remote.."),
-            received = udp::receive(&can_socket, &udp_receiver, udp_sender.local_addr().context("Getting local addr from socket..")?).fuse() => received.context("Trying to receive frames from the remote.."),
+        let force_after = Duration::from_millis(args.force_after_ms as u64);
+        let local_address = udp_sender
+            .local_addr()
+            .context("Getting local addr from socket..")?;
+
+        if args.reliable || args.timestamps {
+            // Both reliable delivery and timestamping run on the stateful send/receive loops. Only
+            // the reliable mode needs the control channel over which the receive task forwards the
+            // peer's Acks/Nacks to the send task for retransmission; timestamping alone leaves it
+            // `None` so no acknowledgements are generated.
+            let (control_tx, control_rx) = if args.reliable {
+                let (tx, rx) = futures::channel::mpsc::unbounded();
+                (Some(tx), Some(rx))
+            } else {
+                (None, None)
+            };
+            select! {
+                sent = udp::send(&can_socket, &udp_sender, force_after, &remotes, key.as_ref(), args.compress, args.timestamps, control_rx).fuse() => sent.context("Trying to send frames to the remote.."),
+                received = udp::receive(&can_socket, &udp_receiver, local_address, &remotes, key.as_ref(), control_tx).fuse() => received.context("Trying to receive frames from the remote.."),
+            }
+        } else {
+            // Otherwise the transport is just a framed Sink/Stream the CAN socket forwards across.
+            // `incoming` is only ever driven as a `Stream`, but it still needs `remotes` to filter
+            // which peers it accepts datagrams from.
+            let mut outgoing =
+                CannelloniFramed::new(udp_sender, remotes.clone(), local_address, key, args.compress);
+            let mut incoming =
+                CannelloniFramed::new(udp_receiver, remotes.clone(), local_address, key, args.compress);
+
+            select! {
+                sent = forward_to_tunnel(&can_socket, &mut outgoing, force_after).fuse() => sent.context("Trying to send frames to the remote.."),
+                received = forward_from_tunnel(&can_socket, &mut incoming).fuse() => received.context("Trying to receive frames from the remote.."),
+            }
         }
     }).context("Asynchronously handling sending and receiving..")?;
 
     Ok(())
 }
+
+/// Read frames off the CAN socket and feed them into the framed tunnel, flushing the buffered
+/// bundle whenever the force-after timer elapses.
+async fn forward_to_tunnel(
+    can_socket: &AsyncCanSocket,
+    tunnel: &mut CannelloniFramed,
+    force_after: Duration,
+) -> anyhow::Result<()> {
+    let mut timer = Timer::after(force_after).fuse();
+    loop {
+        select! {
+            frame = can_socket.read_frame().fuse() => {
+                let frame = frame.context("Trying to read CAN frame..")?;
+                tunnel.feed(frame.into()).await?;
+            }
+            _ = &mut timer => {
+                tunnel.flush().await?;
+                timer = Timer::after(force_after).fuse();
+            }
+        }
+    }
+}
+
+/// Drive the framed tunnel's stream, writing every decoded frame onto the CAN socket.
+async fn forward_from_tunnel(
+    can_socket: &AsyncCanSocket,
+    tunnel: &mut CannelloniFramed,
+) -> anyhow::Result<()> {
+    while let Some(frame) = tunnel.next().await {
+        let frame = frame.context("Decoding frame from tunnel..")?;
+        let _ = can_socket.write_frame(&frame).await;
+    }
+    Ok(())
+}
@@ -18,58 +18,95 @@
 
 use anyhow::Context;
 use async_io::Timer;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures::future::Fuse;
 use futures::{select, FutureExt};
 use smol::net::UdpSocket;
+use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::async_can::AsyncCanSocket;
+use crate::async_can::{AsyncCanSocket, CanFrame};
 use crate::proto;
-use crate::proto::{MessageSerializer, SerializeInto};
+use crate::proto::{Control, MessageSerializer};
 
-const UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE: usize = 508;
+pub(crate) const UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE: usize = 508;
+
+/// How many outgoing bundles the reliable sender remembers for possible retransmission.
+const RETRANSMIT_BUFFER_CAPACITY: usize = 256;
+
+/// How long a buffered bundle is kept before it is assumed lost for good and evicted.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub async fn send(
     can_socket: &AsyncCanSocket,
     udp_socket: &UdpSocket,
     force_after: Duration,
-    target: &SocketAddr,
+    targets: &[SocketAddr],
+    key: Option<&[u8; 32]>,
+    compress: bool,
+    timestamps: bool,
+    mut control: Option<UnboundedReceiver<Control>>,
 ) -> anyhow::Result<()> {
-    let mut encoder = MessageSerializer::new();
+    let mut encoder = match key {
+        Some(key) => MessageSerializer::with_key(key),
+        None => MessageSerializer::new(),
+    };
+    encoder.set_compress(compress);
+    encoder.set_timestamps(timestamps);
+    // Sealing appends a nonce and auth tag in `finalize`, so reserve that overhead up front to keep
+    // the finalized bundle within the no-fragment budget when a key is in use.
+    let max_payload = UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE
+        - if key.is_some() {
+            proto::CRYPTO_OVERHEAD
+        } else {
+            0
+        };
+    // Only the reliable mode keeps a retransmission buffer; in fire-and-forget mode there is no
+    // control channel and this stays `None`.
+    let mut retransmit = control.as_ref().map(|_| RetransmitBuffer::new());
     let mut timer: Option<Fuse<Timer>> = None;
 
     loop {
         timer = match timer {
             None => {
-                let frame = can_socket
-                    .read_frame()
-                    .await
-                    .context("Trying to read CAN frame..")?;
-                if encoder.encoded_size() + frame.encoded_size() > UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE
-                {
-                    send_frame(udp_socket, &mut encoder, target)
-                        .await
-                        .context("Sending frame bundle after buffer size limit was reached..")?;
+                select! {
+                    frame = can_socket.read_frame_with_timestamp().fuse() => {
+                        let (frame, captured_at) = frame.context("Trying to read CAN frame..")?;
+                        if encoder.len() + frame_size(&frame, timestamps) > max_payload
+                        {
+                            send_frame(udp_socket, &mut encoder, targets, retransmit.as_mut())
+                                .await
+                                .context("Sending frame bundle after buffer size limit was reached..")?;
+                        }
+                        encoder.push_frame_at(frame, captured_at);
+                        Some(Timer::after(force_after).fuse())
+                    }
+                    control = next_control(&mut control).fuse() => {
+                        handle_control(control, retransmit.as_mut(), udp_socket, targets).await?;
+                        None
+                    }
                 }
-                encoder.push_frame(frame);
-                Some(Timer::after(force_after).fuse())
             }
             Some(mut timer) => {
                 select! {
                     _ = &mut timer => {
-                        send_frame(udp_socket, &mut encoder, target).await.context("Sending frame bundle after timer has expired..")?;
+                        send_frame(udp_socket, &mut encoder, targets, retransmit.as_mut()).await.context("Sending frame bundle after timer has expired..")?;
                         None
                     }
-                    frame = can_socket.read_frame().fuse() => {
-                        let frame = frame.context("Asynchronously reading frame from CAN socket..")?;
-                        if encoder.encoded_size() + frame.encoded_size() > UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE {
-                            send_frame(udp_socket, &mut encoder, target).await.context("Sending frame bundle after buffer size limit was reached..")?;
-                            encoder.push_frame(frame);
+                    control = next_control(&mut control).fuse() => {
+                        handle_control(control, retransmit.as_mut(), udp_socket, targets).await?;
+                        Some(timer)
+                    }
+                    frame = can_socket.read_frame_with_timestamp().fuse() => {
+                        let (frame, captured_at) = frame.context("Asynchronously reading frame from CAN socket..")?;
+                        if encoder.len() + frame_size(&frame, timestamps) > max_payload {
+                            send_frame(udp_socket, &mut encoder, targets, retransmit.as_mut()).await.context("Sending frame bundle after buffer size limit was reached..")?;
+                            encoder.push_frame_at(frame, captured_at);
                             // new timer, since we have sent the last packet..
                             Some(Timer::after(force_after).fuse())
                         } else {
-                            encoder.push_frame(frame);
+                            encoder.push_frame_at(frame, captured_at);
                             Some(timer)
                         }
                     }
@@ -79,17 +116,124 @@ pub async fn send(
     }
 }
 
+/// Encoded size of a frame in the current bundle, accounting for the per-frame timestamp prefix
+/// when the extended timestamp encoding is enabled.
+fn frame_size(frame: &CanFrame, timestamps: bool) -> usize {
+    // `encoded_size` works on the unified `CanAnyFrame`; a `CanFrame` reference does not coerce to
+    // it, so convert first (the same conversion `push_frame` performs before serializing).
+    let frame: socketcan::CanAnyFrame = frame.clone().into();
+    if timestamps {
+        proto::encoded_size_with_timestamp(&frame)
+    } else {
+        proto::encoded_size(&frame)
+    }
+}
+
 async fn send_frame(
     udp_socket: &UdpSocket,
     encoder: &mut MessageSerializer,
-    target: &SocketAddr,
+    targets: &[SocketAddr],
+    retransmit: Option<&mut RetransmitBuffer>,
 ) -> anyhow::Result<()> {
-    let mut serialized = encoder.serialize();
-    udp_socket
-        .send_to(serialized.make_contiguous(), target)
-        .await
-        .map(|_| ())
-        .context("Sending frame bundle via UDP..")
+    let finalized = encoder.finalize();
+    let bytes = finalized.data();
+    for target in targets {
+        udp_socket
+            .send_to(bytes, target)
+            .await
+            .context("Sending frame bundle via UDP..")?;
+    }
+    if let Some(retransmit) = retransmit {
+        // The sequence number lives in the third header byte; remember the bundle so a later NACK
+        // can ask for it again.
+        retransmit.record(bytes[2], bytes.to_vec());
+    }
+    Ok(())
+}
+
+/// Await the next control packet forwarded from the receive task, or pend forever when the sender
+/// is running in fire-and-forget mode (no channel) so `select!` simply ignores this branch.
+async fn next_control(control: &mut Option<UnboundedReceiver<Control>>) -> Control {
+    use futures::StreamExt;
+    match control {
+        Some(rx) => match rx.next().await {
+            Some(control) => control,
+            None => futures::future::pending().await,
+        },
+        None => futures::future::pending().await,
+    }
+}
+
+/// Apply an incoming `Ack`/`Nack` to the retransmission buffer, resending any bundles a `Nack`
+/// still names.
+async fn handle_control(
+    control: Control,
+    retransmit: Option<&mut RetransmitBuffer>,
+    udp_socket: &UdpSocket,
+    targets: &[SocketAddr],
+) -> anyhow::Result<()> {
+    let Some(retransmit) = retransmit else {
+        return Ok(());
+    };
+    retransmit.evict_expired();
+    match control {
+        Control::Ack(sequence_number) => retransmit.acknowledge(sequence_number),
+        Control::Nack(missing) => {
+            for sequence_number in missing {
+                if let Some(bytes) = retransmit.get(sequence_number) {
+                    for target in targets {
+                        udp_socket
+                            .send_to(bytes, target)
+                            .await
+                            .context("Retransmitting bundle after NACK..")?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bounded buffer of recently sent bundles, keyed by sequence number, for NACK-driven resends.
+///
+/// Sequence numbers wrap at 8 bits, so "older than" comparisons use serial-number arithmetic: a
+/// number is considered acknowledged when it sits within the preceding half of the number space.
+struct RetransmitBuffer {
+    entries: VecDeque<(u8, Vec<u8>, Instant)>,
+}
+
+impl RetransmitBuffer {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, sequence_number: u8, bytes: Vec<u8>) {
+        while self.entries.len() >= RETRANSMIT_BUFFER_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries
+            .push_back((sequence_number, bytes, Instant::now()));
+    }
+
+    fn get(&self, sequence_number: u8) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(seq, ..)| *seq == sequence_number)
+            .map(|(_, bytes, _)| bytes.as_slice())
+    }
+
+    fn acknowledge(&mut self, acked: u8) {
+        self.entries
+            .retain(|(seq, ..)| acked.wrapping_sub(*seq) >= 128);
+    }
+
+    fn evict_expired(&mut self) {
+        let now = Instant::now();
+        self.entries
+            .retain(|(_, _, sent)| now.duration_since(*sent) < RETRANSMIT_TIMEOUT);
+    }
 }
 
 /// Listen for packets on a UDP socket, unwrap them and transfer them onto a can socket..
@@ -99,33 +243,283 @@ async fn send_frame(
 /// * `can_socket`: The CAN socket (destination).
 /// * `udp_socket`:  The UDP socket (source).
 /// * `local_address`: The local address of this applications. If the sender address of a packet is the same as this, it will be ignored. Needed for multicast.
+/// * `remotes`: The configured peers. Datagrams from any other source are dropped unless this names a single multicast group.
 ///
 /// returns: ()
 pub async fn receive(
     can_socket: &AsyncCanSocket,
     udp_socket: &UdpSocket,
     local_address: SocketAddr,
+    remotes: &[SocketAddr],
+    key: Option<&[u8; 32]>,
+    control: Option<UnboundedSender<Control>>,
 ) -> anyhow::Result<()> {
+    let crypto = key.map(|key| proto::Crypto::new(key));
+    // Only the reliable mode tracks gaps and answers with acknowledgements.
+    let mut gaps = control.as_ref().map(|_| GapTracker::new());
+    // Persists across bundles so reordering is detected between, not just within, a bundle.
+    let mut reorder = ReorderTracker::new();
     let mut buffer = [0u8; UDP_NO_FRAGMENT_MAX_PAYLOAD_SIZE];
     loop {
         let (n, peer) = udp_socket
             .recv_from(&mut buffer)
             .await
             .context("Failed to read from UDP socket!")?;
-        if peer != local_address {
-            if let Some(decoder) = proto::MessageReader::try_read(&buffer[..n]) {
-                for frame in decoder {
-                    let _ = can_socket.write_frame(&frame).await;
+        if peer == local_address {
+            continue;
+        }
+
+        // The unconnected `send_to`/`recv_from` socket accepts datagrams from anyone who knows the
+        // bind port; only deliver bundles from a configured peer onto the CAN bus. Skipped for the
+        // single-multicast-group case, where legitimate senders are any current group member, not
+        // just `remotes` itself.
+        if !is_known_peer(&peer, remotes) {
+            continue;
+        }
+
+        // Acks/Nacks from the peer belong to our own send task; hand them over and move on.
+        if let Some(packet) = proto::try_read_control(&buffer[..n], crypto.as_ref()).await? {
+            if let Some(control) = &control {
+                let _ = control.unbounded_send(packet);
+            }
+            continue;
+        }
+
+        let reader = match &crypto {
+            Some(crypto) => proto::MessageReader::try_read_encrypted(&buffer[..n], crypto).await?,
+            None => proto::MessageReader::try_read(&buffer[..n]).await?,
+        };
+
+        let Some(reader) = reader else { continue };
+        let sequence_number = reader.sequence_number();
+
+        // In reliable mode, a bundle already accounted for by the gap tracker (a retransmit that
+        // crossed with the original, or one answering a NACK we no longer need) is ACK'd again but
+        // must not be re-delivered to the CAN bus a second time.
+        let deliver = match gaps.as_mut() {
+            Some(gaps) => {
+                let (contiguous, missing, is_new) = gaps.observe(sequence_number);
+                udp_socket
+                    .send_to(&proto::serialize_ack(contiguous, crypto.as_ref()), peer)
+                    .await
+                    .context("Sending ACK to peer..")?;
+                if !missing.is_empty() {
+                    udp_socket
+                        .send_to(&proto::serialize_nack(&missing, crypto.as_ref()), peer)
+                        .await
+                        .context("Sending NACK to peer..")?;
+                }
+                is_new
+            }
+            None => true,
+        };
+
+        if deliver {
+            drain_into_can(can_socket, reader.into_timestamped_stream(), &mut reorder).await;
+        }
+    }
+}
+
+/// Tracks which bundle sequence numbers have been processed so the receiver can acknowledge the
+/// highest contiguous one and name the gaps it is still missing. Comparisons are done with 8-bit
+/// serial-number arithmetic to account for the sender's `wrapping_add(1)` rollover.
+struct GapTracker {
+    expected: u8,
+    seen: HashSet<u8>,
+    started: bool,
+}
+
+impl GapTracker {
+    fn new() -> Self {
+        Self {
+            expected: 0,
+            seen: HashSet::new(),
+            started: false,
+        }
+    }
+
+    /// Register a received sequence number, returning the highest contiguously-processed number,
+    /// any sequence numbers that are now known to be missing, and whether this sequence number was
+    /// newly processed (as opposed to a duplicate delivery of a bundle already accounted for, e.g.
+    /// a retransmit that crossed with the original on the wire).
+    fn observe(&mut self, sequence_number: u8) -> (u8, Vec<u8>, bool) {
+        if !self.started {
+            self.started = true;
+            self.expected = sequence_number.wrapping_add(1);
+            return (sequence_number, Vec::new(), true);
+        }
+
+        let mut missing = Vec::new();
+        // A number within the next half of the space is "ahead" of (or equal to) what we expect;
+        // anything else is a duplicate or an already-accounted retransmission we can ignore.
+        let is_new = if sequence_number.wrapping_sub(self.expected) < 128 {
+            // Already received out of order; a retransmit of it must be ACK'd again but not
+            // re-delivered to the CAN bus.
+            let is_new = self.seen.insert(sequence_number);
+            let mut gap = self.expected;
+            while gap != sequence_number {
+                // Only NACK values we have not already received out of order; a bundle already in
+                // `seen` is present and must not be re-requested or evicted.
+                if !self.seen.contains(&gap) {
+                    missing.push(gap);
                 }
+                gap = gap.wrapping_add(1);
+            }
+            while self.seen.remove(&self.expected) {
+                self.expected = self.expected.wrapping_add(1);
             }
+            is_new
+        } else {
+            false
+        };
+
+        (self.expected.wrapping_sub(1), missing, is_new)
+    }
+}
+
+/// Write every successfully decoded frame of a bundle onto the CAN socket, ignoring individual
+/// frame errors so a single malformed frame does not tear down the whole receive loop.
+///
+/// When frames carry capture timestamps (extended timestamp encoding), the end-to-end transport
+/// latency and any reordering relative to capture order is logged once per bundle rather than once
+/// per frame: at the "dozens of frames per 50 ms bundle" rate this mode targets, a per-frame print
+/// would flood stderr in the steady state. `reorder` is threaded in from the caller so capture order
+/// is tracked across bundles, not just within one.
+async fn drain_into_can(
+    can_socket: &AsyncCanSocket,
+    stream: impl futures::Stream<Item = anyhow::Result<(socketcan::CanAnyFrame, Option<Duration>)>>,
+    reorder: &mut ReorderTracker,
+) {
+    use futures::StreamExt;
+    futures::pin_mut!(stream);
+    let mut stats: Option<LatencyStats> = None;
+    let mut reordered = 0usize;
+    while let Some(entry) = stream.next().await {
+        if let Ok((frame, captured_at)) = entry {
+            if let Some(captured_at) = captured_at {
+                if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                    // Both clocks are expressed against the UNIX epoch; a negative difference means
+                    // the peers' clocks disagree and is clamped to zero rather than reported.
+                    let latency = now.saturating_sub(captured_at);
+                    stats.get_or_insert_with(LatencyStats::default).observe(latency);
+                }
+                if reorder.observe(captured_at) {
+                    reordered += 1;
+                }
+            }
+            let _ = can_socket.write_frame(&frame).await;
         }
     }
+    if let Some(stats) = stats {
+        eprintln!(
+            "frame transport latency over {} frame(s): min={:?} max={:?}{}",
+            stats.count,
+            stats.min,
+            stats.max,
+            if reordered > 0 {
+                format!(", {reordered} frame(s) arrived out of capture order")
+            } else {
+                String::new()
+            }
+        );
+    }
+}
+
+/// Aggregated latency observations for a single bundle.
+#[derive(Default)]
+struct LatencyStats {
+    count: usize,
+    min: Duration,
+    max: Duration,
+}
+
+impl LatencyStats {
+    fn observe(&mut self, latency: Duration) {
+        self.min = if self.count == 0 {
+            latency
+        } else {
+            self.min.min(latency)
+        };
+        self.max = self.max.max(latency);
+        self.count += 1;
+    }
+}
+
+/// Detects frames delivered out of their capture order: capture timestamps should be
+/// non-decreasing across the stream of forwarded frames, so one that is earlier than the last seen
+/// indicates the transport (UDP reordering, or a retransmit in reliable mode) delivered it late.
+struct ReorderTracker {
+    last_captured: Option<Duration>,
+}
+
+impl ReorderTracker {
+    fn new() -> Self {
+        Self {
+            last_captured: None,
+        }
+    }
+
+    /// Record a frame's capture time, returning whether it arrived out of capture order.
+    fn observe(&mut self, captured_at: Duration) -> bool {
+        let reordered = self.last_captured.is_some_and(|last| captured_at < last);
+        self.last_captured = Some(match self.last_captured {
+            Some(last) => last.max(captured_at),
+            None => captured_at,
+        });
+        reordered
+    }
+}
+
+/// Whether `remotes` names a single multicast group rather than a list of unicast peers. In that
+/// case any current group member may legitimately be the sender, so the caller should not filter
+/// incoming datagrams by source address.
+fn is_single_multicast(remotes: &[SocketAddr]) -> bool {
+    matches!(remotes, [remote] if remote.ip().is_multicast())
+}
+
+/// Whether `peer` is one of the configured `remotes`, or the socket is bound to a single multicast
+/// group (where the sender is not necessarily one of `remotes` itself). Datagrams from anyone else
+/// arrive on the unconnected `send_to`/`recv_from` socket just like a legitimate peer's, so callers
+/// must check this before acting on a decoded bundle.
+pub(crate) fn is_known_peer(peer: &SocketAddr, remotes: &[SocketAddr]) -> bool {
+    is_single_multicast(remotes) || remotes.contains(peer)
 }
 
 pub async fn create_udp_sockets(
     local: &SocketAddr,
-    remote: &SocketAddr,
+    remotes: &[SocketAddr],
 ) -> anyhow::Result<(UdpSocket, UdpSocket)> {
+    // Multicast only makes sense for a single group address; a list of unicast peers is handled by
+    // the unconnected `send_to`/`recv_from` path below.
+    if let [remote] = remotes {
+        if let Some(sockets) = create_multicast_sockets(local, remote).await? {
+            return Ok(sockets);
+        }
+    }
+
+    for remote in remotes {
+        match (remote, local) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {}
+            _ => anyhow::bail!(
+                "Must specify EITHER IPv4 or IPv6 for both bind and all remote addresses!"
+            ),
+        }
+    }
+
+    // A single unconnected socket fans bundles out to every peer and accepts from any of them;
+    // `connect` would pin exactly one peer and is therefore unusable for point-to-multipoint.
+    let udp_socket = bind_simple(local)
+        .await
+        .context("Binding unicast socket..")?;
+    Ok((udp_socket.clone(), udp_socket))
+}
+
+/// Build the sender/receiver socket pair for a multicast group, or `None` when `remote` is not a
+/// multicast address and the caller should fall back to the unicast path.
+async fn create_multicast_sockets(
+    local: &SocketAddr,
+    remote: &SocketAddr,
+) -> anyhow::Result<Option<(UdpSocket, UdpSocket)>> {
     match (remote, local) {
         (SocketAddr::V4(remote), SocketAddr::V4(local)) if remote.ip().is_multicast() => {
             let udp_receiver = UdpSocket::bind(remote)
@@ -138,7 +532,7 @@ pub async fn create_udp_sockets(
             let udp_sender = UdpSocket::bind(local)
                 .await
                 .context(format!("Binding sender socket to local address: {local}.."))?;
-            Ok((udp_sender, udp_receiver))
+            Ok(Some((udp_sender, udp_receiver)))
         }
         (SocketAddr::V6(remote), SocketAddr::V6(local)) if remote.ip().is_multicast() => {
             let udp_receiver = UdpSocket::bind(remote)
@@ -151,28 +545,14 @@ pub async fn create_udp_sockets(
             let udp_sender = UdpSocket::bind(local)
                 .await
                 .context(format!("Binding sender socket to local address: {local}.."))?;
-            Ok((udp_sender, udp_receiver))
+            Ok(Some((udp_sender, udp_receiver)))
         }
-        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
-            let udp_socket = bind_simple(local, remote)
-                .await
-                .context("Binding unicast socket..")?;
-            Ok((udp_socket.clone(), udp_socket))
-        }
-        _ => anyhow::bail!("Must specify EITHER IPv4 or IPv6 for both bind and connect addresses!"),
+        _ => Ok(None),
     }
 }
 
-async fn bind_simple(
-    local_address: &SocketAddr,
-    remote_address: &SocketAddr,
-) -> anyhow::Result<UdpSocket> {
-    let udp_socket = UdpSocket::bind(local_address)
-        .await
-        .context(format!("Binding to local address: {local_address}.."))?;
-    udp_socket
-        .connect(remote_address)
+async fn bind_simple(local_address: &SocketAddr) -> anyhow::Result<UdpSocket> {
+    UdpSocket::bind(local_address)
         .await
-        .context(format!("Connecting to remote address: {remote_address}.."))?;
-    Ok(udp_socket)
+        .context(format!("Binding to local address: {local_address}.."))
 }